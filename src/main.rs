@@ -1,10 +1,55 @@
 use std::collections::HashMap;
 
+use dashmap::DashMap;
 use lazy_static::lazy_static;
+use pest::Parser;
+use pest_derive::Parser;
 use regex::Regex;
+use ropey::Rope;
+use serde::Deserialize;
 use tower_lsp::jsonrpc::Result;
 use tower_lsp::{lsp_types::*, Server};
 use tower_lsp::{LanguageServer, LspService};
+use unicode_security::skeleton;
+
+/// The `workspace/configuration` section a user configures rules under,
+/// e.g. `{"programmerhumor": {"rules": {"4": {"severity": 2}}}}`.
+const CONFIG_SECTION: &str = "programmerhumor";
+
+fn default_rule_enabled() -> bool {
+    true
+}
+
+/// A user's override for a single numbered rule: whether it fires at all,
+/// and at what severity. Mirrors clippy's per-lint `allow`/level model.
+#[derive(Debug, Clone, Copy, Deserialize)]
+struct RuleSetting {
+    #[serde(default = "default_rule_enabled")]
+    enabled: bool,
+    #[serde(default)]
+    severity: Option<DiagnosticSeverity>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ProgrammerhumorConfig {
+    #[serde(default)]
+    rules: HashMap<i32, RuleSetting>,
+}
+
+#[derive(Parser)]
+#[grammar = "comment.pest"]
+struct CommentParser;
+
+/// Where the hosted explanation for each numbered rule lives, so editors can
+/// render a clickable reference alongside the diagnostic.
+const RULE_DOCS_BASE_URL: &str =
+    "https://github.com/Thirdegree/programmerhumor-lsp/blob/main/docs/rules.md";
+
+fn rule_code_description(code: i32) -> CodeDescription {
+    CodeDescription {
+        href: Url::parse(&format!("{RULE_DOCS_BASE_URL}#rule-{code}")).unwrap(),
+    }
+}
 
 fn make_return_diagnostic(line_no: u32) -> Diagnostic {
     Diagnostic {
@@ -20,6 +65,7 @@ fn make_return_diagnostic(line_no: u32) -> Diagnostic {
         },
         severity: Some(DiagnosticSeverity::ERROR),
         code: Some(NumberOrString::Number(3)),
+        code_description: Some(rule_code_description(3)),
         message: "All comments must return a value".to_string(),
         ..Default::default()
     }
@@ -39,6 +85,7 @@ fn make_semicolon_diagnostic(line_no: u32, char_no: u32) -> Diagnostic {
         },
         severity: Some(DiagnosticSeverity::ERROR),
         code: Some(NumberOrString::Number(4)),
+        code_description: Some(rule_code_description(4)),
         message: "For comments, every sentence must end with a semicolon".to_string(),
         ..Default::default()
     }
@@ -58,12 +105,17 @@ fn make_import_diagnostic() -> Diagnostic {
         },
         severity: Some(DiagnosticSeverity::ERROR),
         code: Some(NumberOrString::Number(2)),
+        code_description: Some(rule_code_description(2)),
         message: "All posts and comments should start with an \"import\" declaration.".to_string(),
         ..Default::default()
     }
 }
 
-fn make_link_rick_roll_diagnostic(line_no: u32, char_pos: u32) -> Diagnostic {
+fn make_link_rick_roll_diagnostic(
+    line_no: u32,
+    char_pos: u32,
+    related_information: Vec<DiagnosticRelatedInformation>,
+) -> Diagnostic {
     Diagnostic {
                 range: Range {
                     start: Position {
@@ -77,68 +129,175 @@ fn make_link_rick_roll_diagnostic(line_no: u32, char_pos: u32) -> Diagnostic {
                 },
                 severity: Some(DiagnosticSeverity::ERROR),
                 code: Some(NumberOrString::Number(5)),
+                code_description: Some(rule_code_description(5)),
+                related_information: Some(related_information),
                 message: "Every post linking to something must contain a second, identical-looking link to a rick-roll".to_string(),
                 ..Default::default()
             }
 }
-async fn compute_diagnostics(content: &str) -> Vec<Diagnostic> {
+/// Build the machine-applicable fix for a single diagnostic, keyed off its numeric `code`.
+/// Mirrors clippy's `span_lint_and_sugg`: one lint, one concrete suggestion.
+fn code_action_for_diagnostic(uri: &Url, diagnostic: &Diagnostic) -> Option<CodeAction> {
+    let code = match &diagnostic.code {
+        Some(NumberOrString::Number(n)) => *n,
+        _ => return None,
+    };
+    let (title, edit) = match code {
+        2 => (
+            "Insert \"import humor\" declaration",
+            TextEdit {
+                range: Range {
+                    start: Position {
+                        line: 0,
+                        character: 0,
+                    },
+                    end: Position {
+                        line: 0,
+                        character: 0,
+                    },
+                },
+                new_text: "import humor\n".to_string(),
+            },
+        ),
+        4 => (
+            "Insert missing semicolon",
+            TextEdit {
+                range: Range {
+                    start: diagnostic.range.start,
+                    end: diagnostic.range.start,
+                },
+                new_text: ";".to_string(),
+            },
+        ),
+        3 => (
+            "Append \"return;\"",
+            TextEdit {
+                range: Range {
+                    start: Position {
+                        line: diagnostic.range.start.line,
+                        character: u32::MAX,
+                    },
+                    end: Position {
+                        line: diagnostic.range.start.line,
+                        character: u32::MAX,
+                    },
+                },
+                new_text: " return;".to_string(),
+            },
+        ),
+        5 => (
+            "Insert matching rick-roll link",
+            TextEdit {
+                range: Range {
+                    start: diagnostic.range.start,
+                    end: diagnostic.range.start,
+                },
+                new_text:
+                    "[rickroll](https://www.youtube.com/watch?v=dQw4w9WgXcQ)".to_string(),
+            },
+        ),
+        _ => return None,
+    };
+    let mut changes = HashMap::new();
+    changes.insert(uri.clone(), vec![edit]);
+    Some(CodeAction {
+        title: title.to_string(),
+        kind: Some(CodeActionKind::QUICKFIX),
+        diagnostics: Some(vec![diagnostic.clone()]),
+        edit: Some(WorkspaceEdit {
+            changes: Some(changes),
+            ..Default::default()
+        }),
+        is_preferred: Some(true),
+        ..Default::default()
+    })
+}
+
+async fn compute_diagnostics(uri: &Url, content: &str) -> Vec<Diagnostic> {
     let mut diagnostics = vec![];
-    let mut content_lines = content.lines().peekable();
-    // rule 2
-    if let Some(first_line) = content_lines.next() {
-        lazy_static! {
-            static ref IMPORT_MATCH: Regex = Regex::new(r"(?i)\bimport\b").unwrap(); // case-insensitive
-        }
-        if !IMPORT_MATCH.is_match(first_line) {
-            diagnostics.push(make_import_diagnostic());
-        }
-        if content_lines.peek().is_none() {
-            // import line is last line, MUST be missing return
-            diagnostics.push(make_return_diagnostic(0))
-        }
-    }
-    let mut line_no = 1;
+
+    // Parse the whole comment with the `comment.pest` grammar instead of
+    // stitching its structure back together from regexes. This gives us
+    // real spans, and a `word` token is only ever text that isn't part of a
+    // markdown link, so a "." inside a URL, a decimal, or a numbered list
+    // item never gets mistaken for a sentence end.
+    let comment = match CommentParser::parse(Rule::comment, content) {
+        Ok(mut pairs) => pairs.next().unwrap(),
+        // The grammar is built to consume any character (see `stray`), so
+        // this should never actually happen.
+        Err(_) => return diagnostics,
+    };
+    let lines: Vec<_> = comment
+        .into_inner()
+        .filter(|pair| pair.as_rule() == Rule::line)
+        .collect();
+
     let mut found_links: HashMap<String, Vec<(String, u32, u32)>> = HashMap::new();
-    while let Some(line) = content_lines.next() {
-        if content_lines.peek().is_some() {
-            // Rule 4
+
+    for (line_no, line) in lines.iter().enumerate() {
+        let line_no = line_no as u32;
+        let is_first = line_no == 0;
+        let is_last = line_no as usize == lines.len() - 1;
+
+        if is_first {
+            // rule 2
             lazy_static! {
-                // either a non-space then a period then a space, OR anything then anything
-                // other than a semicolon then end of line
-                // this is a bit iffy, because it also flags e.g. numbered lists. but idk how
-                // the automod config looks, so we'll go with it.
-                static ref SENTENCE_END_MATCH: Regex = Regex::new(r"\w\.\s|[^.]+[^;]$").unwrap();
+                static ref IMPORT_MATCH: Regex = Regex::new(r"(?i)\bimport\b").unwrap(); // case-insensitive
             }
-            for found_match in SENTENCE_END_MATCH.find_iter(line) {
-                diagnostics.push(make_semicolon_diagnostic(
-                    line_no,
-                    (found_match.end() - 2) as u32,
-                ))
+            if !IMPORT_MATCH.is_match(line.as_str()) {
+                diagnostics.push(make_import_diagnostic());
             }
-        } else {
-            // Rule 3
+        }
+
+        if is_last {
+            // rule 3
             lazy_static! {
                 static ref RETURN_MATCH: Regex = Regex::new(r"(?i)\breturn\b").unwrap(); // case-insensitive
             }
-            if !RETURN_MATCH.is_match(line) {
-                diagnostics.push(make_return_diagnostic(line_no))
+            if !RETURN_MATCH.is_match(line.as_str()) {
+                diagnostics.push(make_return_diagnostic(line_no));
             }
         }
-        // Rule 5
-        lazy_static! {
-            // SHOULD match anything like [link text](https://url.com)
-            // Technically we should also be checking the link text is the same, but lazy atm.
-            // Maybe later
-            static ref MARKDOWN_LINK_MATCH: Regex = Regex::new(r"\[([^]]+)\]\(([^)]+)\)").unwrap();
+
+        // The import line is only checked against rule 2 above (and rule 3,
+        // when it is also the last line): like
+        // the original regex implementation, it isn't scanned for
+        // semicolons (rule 4) or links (rule 5).
+        if is_first {
+            continue;
         }
-        for capture in MARKDOWN_LINK_MATCH.captures_iter(line) {
-            let m = capture.get(0).unwrap();
-            found_links
-                .entry(capture[1].to_string())
-                .or_default()
-                .push((capture[2].to_string(), line_no, m.start() as u32));
+
+        for run in line.clone().into_inner() {
+            // rule 4: a whole sentence (which may contain markdown links)
+            // that never reached a terminating ";". A link in the middle
+            // of a sentence doesn't split it into separate fragments.
+            if run.as_rule() == Rule::unterminated_run && !is_last {
+                let (_, col) = run.as_span().end_pos().line_col();
+                diagnostics.push(make_semicolon_diagnostic(line_no, (col - 1) as u32));
+            }
+            if run.as_rule() != Rule::terminated_run && run.as_rule() != Rule::unterminated_run {
+                continue;
+            }
+            // rule 5: collect markdown links (from either kind of run),
+            // grouped by display text
+            for token in run.into_inner() {
+                if token.as_rule() != Rule::markdown_link {
+                    continue;
+                }
+                let (_, col) = token.as_span().start_pos().line_col();
+                let mut inner = token.into_inner();
+                let text = inner.next().unwrap().as_str();
+                let url = inner.next().unwrap().as_str().to_string();
+                // TR39 confusable skeleton: two links are "identical-looking"
+                // when their display text reduces to the same skeleton, not
+                // just when their bytes match.
+                let text_skeleton: String = skeleton(text).collect();
+                found_links
+                    .entry(text_skeleton)
+                    .or_default()
+                    .push((url, line_no, (col - 1) as u32));
+            }
         }
-        line_no += 1;
     }
 
     for links in found_links.values() {
@@ -146,8 +305,35 @@ async fn compute_diagnostics(content: &str) -> Vec<Diagnostic> {
             .iter()
             .any(|(link, _, _)| link == r"https://www.youtube.com/watch?v=dQw4w9WgXcQ")
         {
-            for (_, line_no, char) in links {
-                diagnostics.push(make_link_rick_roll_diagnostic(*line_no, *char))
+            for (i, (_, line_no, char)) in links.iter().enumerate() {
+                // Point from this link back at the other links in its group,
+                // so the editor can show "expected a matching rick-roll here".
+                let related_information = links
+                    .iter()
+                    .enumerate()
+                    .filter(|(j, _)| *j != i)
+                    .map(|(_, (_, other_line, other_char))| DiagnosticRelatedInformation {
+                        location: Location {
+                            uri: uri.clone(),
+                            range: Range {
+                                start: Position {
+                                    line: *other_line,
+                                    character: *other_char,
+                                },
+                                end: Position {
+                                    line: *other_line,
+                                    character: *other_char,
+                                },
+                            },
+                        },
+                        message: "expected a matching rick-roll link here".to_string(),
+                    })
+                    .collect();
+                diagnostics.push(make_link_rick_roll_diagnostic(
+                    *line_no,
+                    *char,
+                    related_information,
+                ))
             }
         }
     }
@@ -155,6 +341,37 @@ async fn compute_diagnostics(content: &str) -> Vec<Diagnostic> {
     diagnostics
 }
 
+/// Convert an LSP `Position` (UTF-16 code units, per the spec) into a char
+/// offset into `rope` that `Rope::insert`/`Rope::remove` can use.
+fn position_to_char_idx(rope: &Rope, position: Position) -> usize {
+    let line_char_idx = rope.line_to_char(position.line as usize);
+    let line = rope.line(position.line as usize);
+    let mut utf16_units = 0usize;
+    let mut char_offset = 0usize;
+    for ch in line.chars() {
+        if utf16_units >= position.character as usize {
+            break;
+        }
+        utf16_units += ch.len_utf16();
+        char_offset += 1;
+    }
+    line_char_idx + char_offset
+}
+
+/// Splice a single `didChange` event into `rope` in place.
+fn apply_content_change(rope: &mut Rope, change: TextDocumentContentChangeEvent) {
+    match change.range {
+        Some(range) => {
+            let start = position_to_char_idx(rope, range.start);
+            let end = position_to_char_idx(rope, range.end);
+            rope.remove(start..end);
+            rope.insert(start, &change.text);
+        }
+        // A client is always allowed to send the full text instead of a range.
+        None => *rope = Rope::from_str(&change.text),
+    }
+}
+
 /// Implement the current rules for styling an r/programmerhumor comment
 ///     1. All post titles must be in camelCase
 ///         Ignored for now, this lsp looks at comment bodies
@@ -164,6 +381,71 @@ async fn compute_diagnostics(content: &str) -> Vec<Diagnostic> {
 ///     5. Every post linking to something must contain a second, identical-looking link to a rick-roll
 struct Backend {
     client: tower_lsp::Client,
+    documents: DashMap<Url, Rope>,
+    rules: DashMap<i32, RuleSetting>,
+}
+
+impl Backend {
+    /// Apply the user's per-rule enable/severity overrides, dropping
+    /// diagnostics for disabled rules and demoting/promoting the rest.
+    fn apply_rule_config(&self, diagnostics: Vec<Diagnostic>) -> Vec<Diagnostic> {
+        diagnostics
+            .into_iter()
+            .filter_map(|mut diagnostic| {
+                let code = match &diagnostic.code {
+                    Some(NumberOrString::Number(n)) => *n,
+                    _ => return Some(diagnostic),
+                };
+                match self.rules.get(&code) {
+                    None => Some(diagnostic),
+                    Some(setting) if !setting.enabled => None,
+                    Some(setting) => {
+                        if let Some(severity) = setting.severity {
+                            diagnostic.severity = Some(severity);
+                        }
+                        Some(diagnostic)
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Pull the `programmerhumor` section from the client's configuration
+    /// and replace our resolved per-rule settings with it.
+    async fn refresh_config(&self) {
+        let items = vec![ConfigurationItem {
+            scope_uri: None,
+            section: Some(CONFIG_SECTION.to_string()),
+        }];
+        let Ok(mut values) = self.client.configuration(items).await else {
+            return;
+        };
+        let Some(value) = values.pop() else {
+            return;
+        };
+        let Ok(config) = serde_json::from_value::<ProgrammerhumorConfig>(value) else {
+            return;
+        };
+        self.rules.clear();
+        for (code, setting) in config.rules {
+            self.rules.insert(code, setting);
+        }
+    }
+
+    /// Re-run diagnostics for every open document, e.g. after the user
+    /// changes a rule's severity or disables it.
+    async fn republish_all_diagnostics(&self) {
+        let uris: Vec<Url> = self.documents.iter().map(|entry| entry.key().clone()).collect();
+        for uri in uris {
+            let Some(rope) = self.documents.get(&uri) else {
+                continue;
+            };
+            let content = rope.to_string();
+            drop(rope);
+            let diagnostics = self.apply_rule_config(compute_diagnostics(&uri, &content).await);
+            self.client.publish_diagnostics(uri, diagnostics, None).await;
+        }
+    }
 }
 
 #[tower_lsp::async_trait]
@@ -172,32 +454,63 @@ impl LanguageServer for Backend {
         Ok(InitializeResult {
             capabilities: ServerCapabilities {
                 text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                    TextDocumentSyncKind::FULL,
+                    TextDocumentSyncKind::INCREMENTAL,
                 )),
+                code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
 
                 ..Default::default()
             },
             ..Default::default()
         })
     }
+
+    async fn initialized(&self, _: InitializedParams) {
+        self.refresh_config().await;
+    }
+
+    async fn did_change_configuration(&self, _: DidChangeConfigurationParams) {
+        self.refresh_config().await;
+        self.republish_all_diagnostics().await;
+    }
+
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        let rope = Rope::from_str(&params.text_document.text);
+        let diagnostics = self.apply_rule_config(
+            compute_diagnostics(&params.text_document.uri, &rope.to_string()).await,
+        );
+        self.documents.insert(params.text_document.uri.clone(), rope);
         self.client
-            .publish_diagnostics(
-                params.text_document.uri,
-                compute_diagnostics(&params.text_document.text).await,
-                None,
-            )
+            .publish_diagnostics(params.text_document.uri, diagnostics, None)
             .await;
     }
 
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
-        self.client
-            .publish_diagnostics(
-                params.text_document.uri,
-                compute_diagnostics(&params.content_changes.first().unwrap().text).await,
-                None,
-            )
-            .await;
+        let uri = params.text_document.uri;
+        let mut rope = self.documents.entry(uri.clone()).or_insert_with(Rope::new);
+        for change in params.content_changes {
+            apply_content_change(&mut rope, change);
+        }
+        let content = rope.to_string();
+        drop(rope);
+
+        let diagnostics = self.apply_rule_config(compute_diagnostics(&uri, &content).await);
+        self.client.publish_diagnostics(uri, diagnostics, None).await;
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        self.documents.remove(&params.text_document.uri);
+    }
+
+    async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
+        let uri = &params.text_document.uri;
+        let actions: CodeActionResponse = params
+            .context
+            .diagnostics
+            .iter()
+            .filter_map(|diagnostic| code_action_for_diagnostic(uri, diagnostic))
+            .map(CodeActionOrCommand::CodeAction)
+            .collect();
+        Ok(Some(actions))
     }
 
     async fn shutdown(&self) -> Result<()> {
@@ -210,7 +523,74 @@ async fn main() -> Result<()> {
     let stdin = tokio::io::stdin();
     let stdout = tokio::io::stdout();
 
-    let (service, socket) = LspService::new(|client| Backend { client });
+    let (service, socket) = LspService::new(|client| Backend {
+        client,
+        documents: DashMap::new(),
+        rules: DashMap::new(),
+    });
     Server::new(stdin, stdout, socket).serve(service).await;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code4_count(diagnostics: &[Diagnostic]) -> usize {
+        diagnostics
+            .iter()
+            .filter(|d| d.code == Some(NumberOrString::Number(4)))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn bracket_notation_does_not_fragment_a_sentence() {
+        let uri = Url::parse("file:///test.txt").unwrap();
+        let content = "import humor\nwhen array[0] crashes my code;\nreturn;";
+        let diagnostics = compute_diagnostics(&uri, content).await;
+        assert_eq!(code4_count(&diagnostics), 0);
+    }
+
+    #[tokio::test]
+    async fn an_unterminated_sentence_with_a_stray_bracket_is_flagged_once() {
+        let uri = Url::parse("file:///test.txt").unwrap();
+        let content = "import humor\nthis is a [ broken sentence\nreturn;";
+        let diagnostics = compute_diagnostics(&uri, content).await;
+        assert_eq!(code4_count(&diagnostics), 1);
+    }
+
+    #[tokio::test]
+    async fn confusable_link_text_is_grouped_with_its_lookalike() {
+        let uri = Url::parse("file:///test.txt").unwrap();
+        // "а" in the second link is Cyrillic (U+0430), not Latin "a", but it
+        // skeletonizes to the same string, so the two links should be
+        // treated as one group lacking a rick-roll, not two separate ones.
+        let content = "import humor\nsee [a](https://example.com) and [а](https://example.org);\nreturn;";
+        let diagnostics = compute_diagnostics(&uri, content).await;
+        let rule5: Vec<_> = diagnostics
+            .iter()
+            .filter(|d| d.code == Some(NumberOrString::Number(5)))
+            .collect();
+        assert_eq!(rule5.len(), 2);
+        assert_eq!(
+            rule5[0].related_information.as_ref().unwrap().len(),
+            1,
+            "each link in the group should point back at exactly the other one"
+        );
+    }
+
+    #[test]
+    fn position_to_char_idx_counts_utf16_units_not_chars() {
+        // "\u{1F600}" (a grinning face emoji) is one `char` but two UTF-16
+        // code units, so the LSP position after it is 2, not 1.
+        let rope = Rope::from_str("\u{1F600}bc");
+        let idx = position_to_char_idx(
+            &rope,
+            Position {
+                line: 0,
+                character: 2,
+            },
+        );
+        assert_eq!(idx, 1);
+    }
+}